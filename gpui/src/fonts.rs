@@ -5,17 +5,105 @@ use crate::{
 use anyhow::{anyhow, Result};
 use font_kit::metrics::Metrics;
 pub use font_kit::properties::{Properties, Weight};
+use lru::LruCache;
 use parking_lot::{RwLock, RwLockUpgradableReadGuard};
-use std::{collections::HashMap, sync::Arc};
+#[cfg(test)]
+use pathfinder_geometry::rect::RectF;
+use std::{
+    collections::HashMap,
+    hash::{Hash, Hasher},
+    num::NonZeroUsize,
+    sync::Arc,
+};
+use thiserror::Error;
+use unicode_bidi::BidiInfo;
 
 pub type GlyphId = u32;
 
+#[derive(Copy, Clone, Debug, Error, Eq, PartialEq)]
+pub enum FontCacheError {
+    #[error("font does not contain a glyph for {0:?}")]
+    MissingGlyph(char),
+    #[error("font id is not registered with this font cache")]
+    MissingFont,
+    #[error("font has not finished loading")]
+    FontNotLoaded,
+    #[error("failed to rasterize glyph")]
+    RasterizationFailed,
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub struct FamilyId(usize);
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
 pub struct FontId(pub usize);
 
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TextDirection {
+    Ltr,
+    Rtl,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShapedGlyph {
+    pub glyph_id: GlyphId,
+    // Byte offset into the shaped text, not a char index.
+    pub cluster: usize,
+    pub x_advance: f32,
+    pub x_offset: f32,
+    pub y_offset: f32,
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct SubpixelOffset {
+    x: u8,
+}
+
+impl SubpixelOffset {
+    const QUANTIZATION_STEPS: u8 = 4;
+
+    pub fn quantize(offset: f32) -> Self {
+        let steps = Self::QUANTIZATION_STEPS as f32;
+        let x = (offset.fract().abs() * steps).round() as u8 % Self::QUANTIZATION_STEPS;
+        Self { x }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct GlyphKey {
+    pub font_id: FontId,
+    pub glyph_id: GlyphId,
+    pub font_size: f32,
+    pub subpixel_offset: SubpixelOffset,
+}
+
+impl Eq for GlyphKey {}
+
+impl PartialEq for GlyphKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.font_id == other.font_id
+            && self.glyph_id == other.glyph_id
+            && self.font_size.to_bits() == other.font_size.to_bits()
+            && self.subpixel_offset == other.subpixel_offset
+    }
+}
+
+impl Hash for GlyphKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.font_id.hash(state);
+        self.glyph_id.hash(state);
+        self.font_size.to_bits().hash(state);
+        self.subpixel_offset.hash(state);
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GlyphImage {
+    pub origin: Vector2F,
+    pub size: Vector2F,
+    pub bytes: Vec<u8>,
+}
+
 pub struct FontCache(RwLock<FontCacheState>);
 
 pub struct FontCacheState {
@@ -23,6 +111,10 @@ pub struct FontCacheState {
     families: Vec<Family>,
     font_selections: HashMap<FamilyId, HashMap<Properties, FontId>>,
     metrics: HashMap<FontId, Metrics>,
+    fallback_families: Vec<FamilyId>,
+    fallback_resolutions: LruCache<(FontId, char), FontId>,
+    shaped_lines: LruCache<(FontId, u32, String), Vec<ShapedGlyph>>,
+    glyphs: LruCache<GlyphKey, Arc<GlyphImage>>,
 }
 
 unsafe impl Send for FontCache {}
@@ -33,12 +125,20 @@ struct Family {
 }
 
 impl FontCache {
+    const GLYPH_CACHE_SIZE: usize = 4096;
+    const SHAPED_LINE_CACHE_SIZE: usize = 4096;
+    const FALLBACK_CACHE_SIZE: usize = 4096;
+
     pub fn new(fonts: Arc<dyn platform::FontSystem>) -> Self {
         Self(RwLock::new(FontCacheState {
             fonts,
             families: Vec::new(),
             font_selections: HashMap::new(),
             metrics: HashMap::new(),
+            fallback_families: Vec::new(),
+            fallback_resolutions: LruCache::new(NonZeroUsize::new(Self::FALLBACK_CACHE_SIZE).unwrap()),
+            shaped_lines: LruCache::new(NonZeroUsize::new(Self::SHAPED_LINE_CACHE_SIZE).unwrap()),
+            glyphs: LruCache::new(NonZeroUsize::new(Self::GLYPH_CACHE_SIZE).unwrap()),
         }))
     }
 
@@ -60,7 +160,7 @@ impl FontCache {
                 let family_id = FamilyId(state.families.len());
                 for font_id in &font_ids {
                     if state.fonts.glyph_for_char(*font_id, 'm').is_none() {
-                        return Err(anyhow!("font must contain a glyph for the 'm' character"));
+                        return Err(FontCacheError::MissingGlyph('m').into());
                     }
                 }
 
@@ -77,11 +177,144 @@ impl FontCache {
         ))
     }
 
+    pub fn load_fonts_from_bytes(&self, fonts: &[Arc<Vec<u8>>]) -> Result<FamilyId> {
+        let mut state = self.0.write();
+        let font_ids = state.fonts.add_fonts(fonts)?;
+        if font_ids.is_empty() {
+            return Err(anyhow!("no fonts found in the provided font data"));
+        }
+
+        for font_id in &font_ids {
+            if state.fonts.glyph_for_char(*font_id, 'm').is_none() {
+                // Release every font we just registered so none of them are
+                // left orphaned in the platform font system, unreachable
+                // from any `Family`.
+                state.fonts.remove_fonts(&font_ids);
+                return Err(FontCacheError::MissingGlyph('m').into());
+            }
+        }
+
+        let family_id = FamilyId(state.families.len());
+        state.families.push(Family {
+            name: format!("__bytes_family_{}", family_id.0),
+            font_ids,
+        });
+        Ok(family_id)
+    }
+
+    pub fn add_fallback_family(&self, family_id: FamilyId) {
+        self.0.write().fallback_families.push(family_id);
+    }
+
+    pub fn font_for_char(&self, font_id: FontId, ch: char) -> FontId {
+        let state = self.0.upgradable_read();
+        if let Some(resolved) = state.fallback_resolutions.peek(&(font_id, ch)) {
+            return *resolved;
+        }
+
+        let mut state = RwLockUpgradableReadGuard::upgrade(state);
+        let resolved = if state.fonts.glyph_for_char(font_id, ch).is_some() {
+            font_id
+        } else {
+            state
+                .fallback_families
+                .clone()
+                .into_iter()
+                .flat_map(|family_id| state.families[family_id.0].font_ids.clone())
+                .find(|fallback_font_id| {
+                    state.fonts.glyph_for_char(*fallback_font_id, ch).is_some()
+                })
+                .or_else(|| state.fonts.fallback_font_for_char(ch))
+                .unwrap_or(font_id)
+        };
+
+        state.fallback_resolutions.put((font_id, ch), resolved);
+        resolved
+    }
+
+    pub fn shape_line(&self, font_id: FontId, font_size: f32, text: &str) -> Vec<ShapedGlyph> {
+        let cache_key = (font_id, font_size.to_bits(), text.to_string());
+        if let Some(glyphs) = self.0.read().shaped_lines.peek(&cache_key) {
+            return glyphs.clone();
+        }
+
+        // Resolve directional runs with the bidi algorithm first, then split
+        // each into font-fallback sub-runs, so glyphs end up both visually
+        // reordered for RTL and shaped through a font that covers them.
+        let bidi_info = BidiInfo::new(text, None);
+        let mut glyphs = Vec::new();
+        for paragraph in &bidi_info.paragraphs {
+            let (levels, bidi_runs) = bidi_info.visual_runs(paragraph, paragraph.range.clone());
+            for bidi_run in bidi_runs {
+                let direction = if levels[bidi_run.start].is_rtl() {
+                    TextDirection::Rtl
+                } else {
+                    TextDirection::Ltr
+                };
+
+                let mut font_runs: Vec<(FontId, String, usize)> = Vec::new();
+                for (offset, ch) in text[bidi_run.clone()].char_indices() {
+                    let resolved_font_id = self.font_for_char(font_id, ch);
+                    match font_runs.last_mut() {
+                        Some((run_font_id, run_text, _)) if *run_font_id == resolved_font_id => {
+                            run_text.push(ch);
+                        }
+                        _ => font_runs.push((
+                            resolved_font_id,
+                            ch.to_string(),
+                            bidi_run.start + offset,
+                        )),
+                    }
+                }
+
+                // An RTL bidi run places its logically-first sub-run last.
+                if direction == TextDirection::Rtl {
+                    font_runs.reverse();
+                }
+
+                let state = self.0.read();
+                for (run_font_id, run_text, cluster_base) in font_runs {
+                    for mut glyph in
+                        state
+                            .fonts
+                            .layout_line(run_font_id, font_size, &run_text, direction)
+                    {
+                        glyph.cluster += cluster_base;
+                        glyphs.push(glyph);
+                    }
+                }
+            }
+        }
+
+        self.0.write().shaped_lines.put(cache_key, glyphs.clone());
+        glyphs
+    }
+
+    pub fn rasterize_glyph(&self, key: GlyphKey) -> Result<Arc<GlyphImage>, FontCacheError> {
+        if let Some(image) = self.0.read().glyphs.peek(&key) {
+            return Ok(image.clone());
+        }
+
+        let mut state = self.0.write();
+        let image = Arc::new(
+            state
+                .fonts
+                .rasterize_glyph(key)
+                .ok_or(FontCacheError::RasterizationFailed)?,
+        );
+        state.glyphs.put(key, image.clone());
+        Ok(image)
+    }
+
     pub fn default_font(&self, family_id: FamilyId) -> FontId {
         self.select_font(family_id, &Properties::default()).unwrap()
     }
 
-    pub fn select_font(&self, family_id: FamilyId, properties: &Properties) -> Result<FontId> {
+    pub fn select_font(
+        &self,
+        family_id: FamilyId,
+        properties: &Properties,
+    ) -> Result<FontId, FontCacheError> {
         let inner = self.0.upgradable_read();
         if let Some(font_id) = inner
             .font_selections
@@ -95,7 +328,8 @@ impl FontCache {
             let font_id = inner
                 .fonts
                 .select_font(&family.font_ids, properties)
-                .unwrap_or(family.font_ids[0]);
+                .or_else(|| family.font_ids.first().copied())
+                .ok_or(FontCacheError::FontNotLoaded)?;
 
             inner
                 .font_selections
@@ -106,55 +340,183 @@ impl FontCache {
         }
     }
 
-    pub fn metric<F, T>(&self, font_id: FontId, f: F) -> T
+    pub fn metric<F, T>(&self, font_id: FontId, f: F) -> Result<T, FontCacheError>
     where
         F: FnOnce(&Metrics) -> T,
         T: 'static,
     {
         let state = self.0.upgradable_read();
         if let Some(metrics) = state.metrics.get(&font_id) {
-            f(metrics)
+            Ok(f(metrics))
         } else {
-            let metrics = state.fonts.font_metrics(font_id);
+            let metrics = state
+                .fonts
+                .font_metrics(font_id)
+                .ok_or(FontCacheError::MissingFont)?;
             let metric = f(&metrics);
             let mut state = RwLockUpgradableReadGuard::upgrade(state);
             state.metrics.insert(font_id, metrics);
-            metric
+            Ok(metric)
         }
     }
 
-    pub fn bounding_box(&self, font_id: FontId, font_size: f32) -> Vector2F {
-        let bounding_box = self.metric(font_id, |m| m.bounding_box);
-        let width = self.scale_metric(bounding_box.width(), font_id, font_size);
-        let height = self.scale_metric(bounding_box.height(), font_id, font_size);
-        vec2f(width, height)
+    pub fn bounding_box(
+        &self,
+        font_id: FontId,
+        font_size: f32,
+    ) -> Result<Vector2F, FontCacheError> {
+        let bounding_box = self.metric(font_id, |m| m.bounding_box)?;
+        let width = self.scale_metric(bounding_box.width(), font_id, font_size)?;
+        let height = self.scale_metric(bounding_box.height(), font_id, font_size)?;
+        Ok(vec2f(width, height))
     }
 
-    pub fn em_width(&self, font_id: FontId, font_size: f32) -> f32 {
+    pub fn em_width(&self, font_id: FontId, font_size: f32) -> Result<f32, FontCacheError> {
         let state = self.0.read();
-        let glyph_id = state.fonts.glyph_for_char(font_id, 'm').unwrap();
-        let bounds = state.fonts.typographic_bounds(font_id, glyph_id).unwrap();
+        let glyph_id = state
+            .fonts
+            .glyph_for_char(font_id, 'm')
+            .ok_or(FontCacheError::MissingGlyph('m'))?;
+        let bounds = state
+            .fonts
+            .typographic_bounds(font_id, glyph_id)
+            .ok_or(FontCacheError::MissingGlyph('m'))?;
+        drop(state);
         self.scale_metric(bounds.width(), font_id, font_size)
     }
 
-    pub fn line_height(&self, font_id: FontId, font_size: f32) -> f32 {
-        let bounding_box = self.metric(font_id, |m| m.bounding_box);
+    pub fn line_height(&self, font_id: FontId, font_size: f32) -> Result<f32, FontCacheError> {
+        let bounding_box = self.metric(font_id, |m| m.bounding_box)?;
         self.scale_metric(bounding_box.height(), font_id, font_size)
     }
 
-    pub fn cap_height(&self, font_id: FontId, font_size: f32) -> f32 {
-        self.scale_metric(self.metric(font_id, |m| m.cap_height), font_id, font_size)
+    pub fn cap_height(&self, font_id: FontId, font_size: f32) -> Result<f32, FontCacheError> {
+        self.scale_metric(self.metric(font_id, |m| m.cap_height)?, font_id, font_size)
+    }
+
+    pub fn ascent(&self, font_id: FontId, font_size: f32) -> Result<f32, FontCacheError> {
+        self.scale_metric(self.metric(font_id, |m| m.ascent)?, font_id, font_size)
+    }
+
+    pub fn descent(&self, font_id: FontId, font_size: f32) -> Result<f32, FontCacheError> {
+        self.scale_metric(self.metric(font_id, |m| m.descent)?, font_id, font_size)
     }
 
-    pub fn ascent(&self, font_id: FontId, font_size: f32) -> f32 {
-        self.scale_metric(self.metric(font_id, |m| m.ascent), font_id, font_size)
+    pub fn scale_metric(
+        &self,
+        metric: f32,
+        font_id: FontId,
+        font_size: f32,
+    ) -> Result<f32, FontCacheError> {
+        Ok(metric * font_size / self.metric(font_id, |m| m.units_per_em as f32)?)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    pub fn descent(&self, font_id: FontId, font_size: f32) -> f32 {
-        self.scale_metric(self.metric(font_id, |m| m.descent), font_id, font_size)
+    #[test]
+    fn quantize_snaps_to_nearest_quarter_pixel() {
+        assert_eq!(SubpixelOffset::quantize(0.0).x, 0);
+        assert_eq!(SubpixelOffset::quantize(0.1).x, 0);
+        assert_eq!(SubpixelOffset::quantize(0.24).x, 1);
+        assert_eq!(SubpixelOffset::quantize(0.26).x, 1);
+        assert_eq!(SubpixelOffset::quantize(0.49).x, 2);
+        assert_eq!(SubpixelOffset::quantize(0.51).x, 2);
+        assert_eq!(SubpixelOffset::quantize(0.76).x, 3);
+        // Rounds up to a full pixel, which wraps back around to offset 0.
+        assert_eq!(SubpixelOffset::quantize(0.99).x, 0);
+        // Only the fractional part (by magnitude) matters.
+        assert_eq!(SubpixelOffset::quantize(-0.26).x, 1);
+        assert_eq!(SubpixelOffset::quantize(3.26).x, 1);
     }
 
-    pub fn scale_metric(&self, metric: f32, font_id: FontId, font_size: f32) -> f32 {
-        metric * font_size / self.metric(font_id, |m| m.units_per_em as f32)
+    struct TestFontSystem;
+
+    impl platform::FontSystem for TestFontSystem {
+        fn load_family(&self, name: &str) -> Result<Vec<FontId>> {
+            if name == "fallback" {
+                Ok(vec![FontId(1)])
+            } else {
+                Err(anyhow!("unknown family {:?}", name))
+            }
+        }
+
+        fn add_fonts(&self, _fonts: &[Arc<Vec<u8>>]) -> Result<Vec<FontId>> {
+            unimplemented!()
+        }
+
+        fn remove_fonts(&self, _font_ids: &[FontId]) {
+            unimplemented!()
+        }
+
+        fn select_font(&self, font_ids: &[FontId], _properties: &Properties) -> Option<FontId> {
+            font_ids.first().copied()
+        }
+
+        fn glyph_for_char(&self, font_id: FontId, ch: char) -> Option<GlyphId> {
+            match font_id.0 {
+                // The primary test font covers ASCII and Hebrew Alef, but not Bet.
+                0 => (ch.is_ascii() || ch == 'א').then_some(ch as GlyphId),
+                // The fallback family covers everything.
+                1 => Some(ch as GlyphId),
+                _ => None,
+            }
+        }
+
+        fn fallback_font_for_char(&self, _ch: char) -> Option<FontId> {
+            None
+        }
+
+        fn font_metrics(&self, _font_id: FontId) -> Option<Metrics> {
+            unimplemented!()
+        }
+
+        fn typographic_bounds(&self, _font_id: FontId, _glyph_id: GlyphId) -> Option<RectF> {
+            unimplemented!()
+        }
+
+        fn layout_line(
+            &self,
+            font_id: FontId,
+            _font_size: f32,
+            text: &str,
+            _direction: TextDirection,
+        ) -> Vec<ShapedGlyph> {
+            text.char_indices()
+                .map(|(offset, ch)| ShapedGlyph {
+                    glyph_id: ((font_id.0 as u32) << 16) | ch as u32,
+                    cluster: offset,
+                    x_advance: 10.,
+                    x_offset: 0.,
+                    y_offset: 0.,
+                })
+                .collect()
+        }
+
+        fn rasterize_glyph(&self, _key: GlyphKey) -> Option<GlyphImage> {
+            unimplemented!()
+        }
+    }
+
+    #[test]
+    fn shape_line_reorders_rtl_sub_runs_and_falls_back_for_missing_glyphs() {
+        let font_cache = FontCache::new(Arc::new(TestFontSystem));
+        let fallback_family = font_cache.load_family(&["fallback"]).unwrap();
+        font_cache.add_fallback_family(fallback_family);
+
+        // "a" (LTR, primary font) followed by an RTL run "אב" where 'א' is
+        // covered by the primary font but 'ב' requires the fallback family.
+        let glyphs = font_cache.shape_line(FontId(0), 16., "aאב");
+        let font_of = |glyph: &ShapedGlyph| glyph.glyph_id >> 16;
+
+        assert_eq!(glyphs.len(), 3);
+        assert_eq!(glyphs[0].cluster, 0); // 'a'
+        assert_eq!(glyphs[1].cluster, 3); // 'ב', visually first within the RTL run
+        assert_eq!(glyphs[2].cluster, 1); // 'א', visually last within the RTL run
+        assert_eq!(font_of(&glyphs[0]), 0);
+        assert_eq!(font_of(&glyphs[1]), 1); // fell back to the registered family
+        assert_eq!(font_of(&glyphs[2]), 0); // covered directly by the primary font
     }
 }